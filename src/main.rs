@@ -2,10 +2,10 @@
 // Copyright (C) 2021-present https://github.com/icedland
 
 use anyhow::Context;
-use hashbrown::HashMap;
-use iced_x86::{Code, CpuidFeature, Decoder, DecoderOptions, Instruction};
+use hashbrown::{HashMap, HashSet};
+use iced_x86::{Code, CpuidFeature, Decoder, DecoderOptions, EncodingKind, Instruction};
 use memmap::Mmap;
-use object::{File, Object, ObjectSection, SectionKind};
+use object::{File, Object, ObjectSection, ObjectSymbol, SectionKind, SymbolKind};
 use std::fs;
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -43,6 +43,36 @@ struct CommandLineOptions {
 
 	#[structopt(long = "ignore-cpuid", help = "Ignores the following CPUID features (','-separated). Matches whole strings.")]
 	ignore_cpuid: Option<String>,
+
+	#[structopt(long = "check-host", help = "Reports CPUID features used by the binary that aren't available on the host CPU")]
+	check_host: bool,
+
+	#[structopt(
+		long = "target-features",
+		help = "Comma-separated set of available features (eg. 'sse4.2,avx2') to check against instead of detecting the host CPU. Implies --check-host."
+	)]
+	target_features: Option<String>,
+
+	#[structopt(long = "min-level", help = "Prints the minimum x86-64 microarchitecture level (v1-v4) required to run the binary")]
+	min_level: bool,
+
+	#[structopt(
+		long,
+		help = "Groups instructions by encoding kind (Legacy, VEX, EVEX, XOP, D3NOW, MVEX) instead of CPUID feature. Can be combined with --cpuid/--ignore-cpuid."
+	)]
+	encoding: bool,
+
+	#[structopt(long = "show-invalid", help = "Reports decode failures and coalesces them into suspected data regions")]
+	show_invalid: bool,
+
+	#[structopt(long, default_value = "text", help = "Output format: 'text' (default) or 'json'")]
+	format: String,
+
+	#[structopt(long = "by-section", help = "Attributes CPUID feature usage to the section each instruction was decoded from")]
+	by_section: bool,
+
+	#[structopt(long = "by-symbol", help = "Attributes CPUID feature usage to the function symbol each instruction falls into. Implies --by-section.")]
+	by_symbol: bool,
 }
 
 #[derive(Default, Clone, Copy, PartialEq, Eq, Hash)]
@@ -56,6 +86,617 @@ struct CpuidInfo {
 	codes: HashMap<Code, CodeInfo>,
 }
 
+/// Maps a `CpuidFeature` to the token `std::arch::is_x86_feature_detected!()` expects.
+///
+/// Features that aren't in this table have no runtime detection support and are
+/// assumed to be present (eg. ancient/always-on features, or ones libstd can't detect).
+const FEATURE_DETECT_TOKENS: &[(CpuidFeature, &str)] = &[
+	(CpuidFeature::SSE, "sse"),
+	(CpuidFeature::SSE2, "sse2"),
+	(CpuidFeature::SSE3, "sse3"),
+	(CpuidFeature::SSSE3, "ssse3"),
+	(CpuidFeature::SSE4_1, "sse4.1"),
+	(CpuidFeature::SSE4_2, "sse4.2"),
+	(CpuidFeature::AVX, "avx"),
+	(CpuidFeature::AVX2, "avx2"),
+	(CpuidFeature::FMA, "fma"),
+	(CpuidFeature::BMI1, "bmi1"),
+	(CpuidFeature::BMI2, "bmi2"),
+	(CpuidFeature::POPCNT, "popcnt"),
+	(CpuidFeature::LZCNT, "lzcnt"),
+	(CpuidFeature::AES, "aes"),
+	(CpuidFeature::PCLMULQDQ, "pclmulqdq"),
+	(CpuidFeature::RDRAND, "rdrand"),
+	(CpuidFeature::RDSEED, "rdseed"),
+	(CpuidFeature::F16C, "f16c"),
+	(CpuidFeature::MOVBE, "movbe"),
+	(CpuidFeature::FXSR, "fxsr"),
+	(CpuidFeature::XSAVE, "xsave"),
+	(CpuidFeature::XSAVEC, "xsavec"),
+	(CpuidFeature::XSAVEOPT, "xsaveopt"),
+	(CpuidFeature::XSAVES, "xsaves"),
+	(CpuidFeature::AVX512F, "avx512f"),
+	(CpuidFeature::AVX512BW, "avx512bw"),
+	(CpuidFeature::AVX512CD, "avx512cd"),
+	(CpuidFeature::AVX512DQ, "avx512dq"),
+	(CpuidFeature::AVX512VL, "avx512vl"),
+];
+
+#[cfg(target_arch = "x86_64")]
+fn is_token_detected(token: &str) -> bool {
+	// `is_x86_feature_detected!` requires a string literal, so this can't be table-driven.
+	match token {
+		"sse" => std::is_x86_feature_detected!("sse"),
+		"sse2" => std::is_x86_feature_detected!("sse2"),
+		"sse3" => std::is_x86_feature_detected!("sse3"),
+		"ssse3" => std::is_x86_feature_detected!("ssse3"),
+		"sse4.1" => std::is_x86_feature_detected!("sse4.1"),
+		"sse4.2" => std::is_x86_feature_detected!("sse4.2"),
+		"avx" => std::is_x86_feature_detected!("avx"),
+		"avx2" => std::is_x86_feature_detected!("avx2"),
+		"fma" => std::is_x86_feature_detected!("fma"),
+		"bmi1" => std::is_x86_feature_detected!("bmi1"),
+		"bmi2" => std::is_x86_feature_detected!("bmi2"),
+		"popcnt" => std::is_x86_feature_detected!("popcnt"),
+		"lzcnt" => std::is_x86_feature_detected!("lzcnt"),
+		"aes" => std::is_x86_feature_detected!("aes"),
+		"pclmulqdq" => std::is_x86_feature_detected!("pclmulqdq"),
+		"rdrand" => std::is_x86_feature_detected!("rdrand"),
+		"rdseed" => std::is_x86_feature_detected!("rdseed"),
+		"f16c" => std::is_x86_feature_detected!("f16c"),
+		"movbe" => std::is_x86_feature_detected!("movbe"),
+		"fxsr" => std::is_x86_feature_detected!("fxsr"),
+		"xsave" => std::is_x86_feature_detected!("xsave"),
+		"xsavec" => std::is_x86_feature_detected!("xsavec"),
+		"xsaveopt" => std::is_x86_feature_detected!("xsaveopt"),
+		"xsaves" => std::is_x86_feature_detected!("xsaves"),
+		"avx512f" => std::is_x86_feature_detected!("avx512f"),
+		"avx512bw" => std::is_x86_feature_detected!("avx512bw"),
+		"avx512cd" => std::is_x86_feature_detected!("avx512cd"),
+		"avx512dq" => std::is_x86_feature_detected!("avx512dq"),
+		"avx512vl" => std::is_x86_feature_detected!("avx512vl"),
+		_ => false,
+	}
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn is_token_detected(_token: &str) -> bool {
+	false
+}
+
+/// The set of feature tokens considered available, either detected from the host CPU
+/// or taken verbatim from `--target-features`.
+fn available_feature_tokens(target_features: &Option<String>) -> HashSet<String> {
+	match target_features {
+		Some(s) => s.split(',').map(|s| s.trim().to_lowercase()).filter(|s| !s.is_empty()).collect(),
+		None => FEATURE_DETECT_TOKENS.iter().map(|&(_, token)| token).filter(|&token| is_token_detected(token)).map(str::to_string).collect(),
+	}
+}
+
+/// Maps a `CpuidFeature` to the x86-64 psABI level (v1-v4) that first requires it.
+/// The classification is cumulative: running v3 code requires the whole of v1 and v2 too.
+const MICROARCH_LEVELS: &[(CpuidFeature, u8)] = &[
+	(CpuidFeature::CMOV, 1),
+	(CpuidFeature::CX8, 1),
+	(CpuidFeature::FPU, 1),
+	(CpuidFeature::FXSR, 1),
+	(CpuidFeature::MMX, 1),
+	(CpuidFeature::SSE, 1),
+	(CpuidFeature::SSE2, 1),
+	(CpuidFeature::CMPXCHG16B, 2),
+	(CpuidFeature::POPCNT, 2),
+	(CpuidFeature::SSE3, 2),
+	(CpuidFeature::SSSE3, 2),
+	(CpuidFeature::SSE4_1, 2),
+	(CpuidFeature::SSE4_2, 2),
+	(CpuidFeature::AVX, 3),
+	(CpuidFeature::AVX2, 3),
+	(CpuidFeature::BMI1, 3),
+	(CpuidFeature::BMI2, 3),
+	(CpuidFeature::F16C, 3),
+	(CpuidFeature::FMA, 3),
+	(CpuidFeature::LZCNT, 3),
+	(CpuidFeature::MOVBE, 3),
+	(CpuidFeature::AVX512F, 4),
+	(CpuidFeature::AVX512BW, 4),
+	(CpuidFeature::AVX512CD, 4),
+	(CpuidFeature::AVX512DQ, 4),
+	(CpuidFeature::AVX512VL, 4),
+];
+
+fn microarch_level(feature: CpuidFeature) -> Option<u8> {
+	MICROARCH_LEVELS.iter().find(|&&(f, _)| f == feature).map(|&(_, level)| level)
+}
+
+/// Prints the "Minimum level" report for `--min-level`: the highest x86-64-vN level
+/// reached by any decoded instruction, plus the feature(s) and example instructions
+/// that forced it. Baseline binaries that need nothing above v1 print just the level.
+fn print_min_level(feature_codes: &HashMap<CpuidFeature, CpuidInfo>) {
+	let max_level = feature_codes.keys().filter_map(|&f| microarch_level(f)).max().unwrap_or(1);
+	println!("Minimum level: x86-64-v{}", max_level);
+	if max_level > 1 {
+		let mut forcing: Vec<_> = feature_codes.iter().filter(|&(&f, _)| microarch_level(f) == Some(max_level)).collect();
+		forcing.sort_unstable_by_key(|&(&f, _)| format!("{:?}", f));
+		for (feature, info) in forcing {
+			println!("\t{:?}", feature);
+			let mut codes: Vec<_> = info.codes.values().copied().collect();
+			codes.sort_unstable_by_key(|info| info.code.op_code().instruction_string());
+			for info in codes {
+				println!("\t\t{}", info.code.op_code().instruction_string());
+			}
+		}
+	}
+}
+
+/// Prints the `--encoding` report: instructions bucketed by `EncodingKind` instead of
+/// CPUID feature, still honoring `--count`/`--percent`/`--instr`/`--opcode`. `--cpuid`/
+/// `--ignore-cpuid` match any one feature in an instruction's (possibly multi-feature)
+/// CPUID label. With `--instr`/`--opcode`, that label ends every instruction line, so eg.
+/// `--encoding --cpuid AVX512F --instr` lists the AVX512F instructions of each encoding
+/// kind together with their CPUID features; without them only the kind names are printed.
+fn print_by_encoding(
+	encoding_codes: &HashMap<EncodingKind, CpuidInfo>, code_cpuid_label: &HashMap<Code, String>, cmd: &CommandLineOptions, total_instrs: usize,
+	show_more_info: bool,
+) {
+	let cpuid_filter = to_cpuid_filter_vec(cmd.cpuid.clone());
+	let ignore_cpuid_filter = to_cpuid_filter_vec(cmd.ignore_cpuid.clone());
+	let mut kinds: Vec<_> = encoding_codes.keys().copied().collect();
+	kinds.sort_unstable_by_key(|&k| format!("{:?}", k));
+	let mut output_vec = Vec::new();
+	for kind in kinds {
+		let mut codes: Vec<_> = encoding_codes[&kind]
+			.codes
+			.values()
+			.copied()
+			.filter(|info| {
+				let label = code_cpuid_label.get(&info.code).map(String::as_str).unwrap_or_default();
+				if !cpuid_filter.is_empty() && !cpuid_filter.iter().any(|s| matches_cpuid_in_label(label, s)) {
+					return false;
+				}
+				ignore_cpuid_filter.is_empty() || !ignore_cpuid_filter.iter().any(|s| matches_cpuid_in_label(label, s))
+			})
+			.collect();
+		if codes.is_empty() {
+			continue;
+		}
+
+		println!("{:?}", kind);
+		if show_more_info {
+			codes.sort_unstable_by_key(|info| (info.code.op_code().instruction_string(), info.code.op_code().op_code_string(), info.code));
+			for info in codes {
+				let opcode = info.code.op_code();
+				output_vec.clear();
+				if cmd.percent {
+					output_vec.push(format!("{:.2}%", (info.count as f64) / (total_instrs as f64) * 100.));
+				}
+				if cmd.count {
+					output_vec.push(format!("{}", info.count));
+				}
+				if cmd.opcode {
+					output_vec.push(opcode.op_code_string().to_string());
+				}
+				if cmd.instr {
+					output_vec.push(opcode.instruction_string().to_string());
+				}
+				output_vec.push(code_cpuid_label.get(&info.code).cloned().unwrap_or_default());
+				println!("\t{}", output_vec.join(" | "));
+			}
+		}
+	}
+}
+
+struct InvalidSpan {
+	section: String,
+	file_offset: u64,
+	len: u64,
+}
+
+/// Tracks `Code::INVALID` decode failures for `--show-invalid`, coalescing consecutive
+/// invalid bytes (within the same section) into contiguous "suspected data" spans.
+#[derive(Default)]
+struct InvalidRegions {
+	total_bytes: u64,
+	invalid_bytes: u64,
+	spans: Vec<InvalidSpan>,
+	current: Option<InvalidSpan>,
+}
+
+impl InvalidRegions {
+	fn record_invalid(&mut self, section: &str, file_offset: u64, len: u64) {
+		if let Some(span) = &mut self.current {
+			if span.section == section && span.file_offset + span.len == file_offset {
+				span.len += len;
+				return;
+			}
+			self.spans.push(self.current.take().unwrap());
+		}
+		self.current = Some(InvalidSpan { section: section.to_string(), file_offset, len });
+	}
+
+	fn finish_section(&mut self) {
+		if let Some(span) = self.current.take() {
+			self.spans.push(span);
+		}
+	}
+}
+
+fn print_invalid_regions(regions: &InvalidRegions) {
+	println!("Total bytes decoded: {}", regions.total_bytes);
+	println!("Invalid bytes: {}", regions.invalid_bytes);
+	println!("Suspected data regions: {}", regions.spans.len());
+	for span in &regions.spans {
+		println!("\t{} +0x{:x} ({} bytes)", span.section, span.file_offset, span.len);
+	}
+}
+
+/// A minimal JSON value type for `--format json`, hand-rolled instead of pulling in
+/// `serde_json` since this is the only feature that needs it.
+enum JsonValue {
+	Bool(bool),
+	UInt(u64),
+	Float(f64),
+	Str(String),
+	Array(Vec<JsonValue>),
+	Object(Vec<(&'static str, JsonValue)>),
+}
+
+impl JsonValue {
+	fn to_pretty_string(&self) -> String {
+		let mut out = String::new();
+		self.write(&mut out, 0);
+		out
+	}
+
+	fn write(&self, out: &mut String, indent: usize) {
+		match self {
+			JsonValue::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+			JsonValue::UInt(u) => out.push_str(&u.to_string()),
+			JsonValue::Float(f) => out.push_str(&format!("{:.2}", f)),
+			JsonValue::Str(s) => out.push_str(&json_quote(s)),
+			JsonValue::Array(items) => Self::write_seq(out, indent, '[', ']', items.iter(), |out, indent, item| item.write(out, indent)),
+			JsonValue::Object(fields) => Self::write_seq(out, indent, '{', '}', fields.iter(), |out, indent, (key, value)| {
+				out.push_str(&json_quote(key));
+				out.push_str(": ");
+				value.write(out, indent);
+			}),
+		}
+	}
+
+	fn write_seq<T>(out: &mut String, indent: usize, open: char, close: char, items: impl ExactSizeIterator<Item = T>, mut write_item: impl FnMut(&mut String, usize, T)) {
+		if items.len() == 0 {
+			out.push(open);
+			out.push(close);
+			return;
+		}
+		out.push(open);
+		out.push('\n');
+		let len = items.len();
+		for (i, item) in items.enumerate() {
+			out.push_str(&"  ".repeat(indent + 1));
+			write_item(out, indent + 1, item);
+			if i + 1 != len {
+				out.push(',');
+			}
+			out.push('\n');
+		}
+		out.push_str(&"  ".repeat(indent));
+		out.push(close);
+	}
+}
+
+fn json_quote(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\t' => out.push_str("\\t"),
+			'\r' => out.push_str("\\r"),
+			c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+fn cpuid_group_json(cpuid: &[CpuidFeature], info: &CpuidInfo, total_instrs: usize) -> JsonValue {
+	let mut instructions: Vec<_> = info.codes.values().copied().collect();
+	instructions.sort_unstable_by_key(|info| (info.code.op_code().instruction_string(), info.code.op_code().op_code_string(), info.code));
+	let group_count: usize = instructions.iter().map(|info| info.count).sum();
+	let instrs_json = instructions
+		.iter()
+		.map(|info| {
+			let opcode = info.code.op_code();
+			JsonValue::Object(vec![
+				("opcode", JsonValue::Str(opcode.op_code_string().to_string())),
+				("instruction", JsonValue::Str(opcode.instruction_string().to_string())),
+				("mnemonic", JsonValue::Str(format!("{:?}", info.code))),
+				("count", JsonValue::UInt(info.count as u64)),
+				("percent", JsonValue::Float((info.count as f64) / (total_instrs as f64) * 100.)),
+			])
+		})
+		.collect();
+	JsonValue::Object(vec![
+		("features", JsonValue::Array(cpuid.iter().map(|&f| JsonValue::Str(format!("{:?}", f))).collect())),
+		("count", JsonValue::UInt(group_count as u64)),
+		("percent", JsonValue::Float((group_count as f64) / (total_instrs as f64) * 100.)),
+		("instructions", JsonValue::Array(instrs_json)),
+	])
+}
+
+fn build_cpuid_groups_json(all_cpuid: &[(String, Vec<CpuidFeature>, CpuidInfo)], cmd: &CommandLineOptions, total_instrs: usize) -> Vec<JsonValue> {
+	let cpuid_filter = to_cpuid_filter_vec(cmd.cpuid.clone());
+	let ignore_cpuid_filter = to_cpuid_filter_vec(cmd.ignore_cpuid.clone());
+	all_cpuid
+		.iter()
+		.filter(|(cpuid_str, cpuid, _)| {
+			if !cmd.all && cpuid.len() == 1 && should_ignore_cpuid(cpuid[0]) {
+				return false;
+			}
+			if !cpuid_filter.is_empty() && !cpuid_filter.iter().any(|s| matches_cpuid(cpuid_str, s)) {
+				return false;
+			}
+			ignore_cpuid_filter.is_empty() || !ignore_cpuid_filter.iter().any(|s| matches_cpuid(cpuid_str, s))
+		})
+		.map(|(_, cpuid, info)| cpuid_group_json(cpuid, info, total_instrs))
+		.collect()
+}
+
+/// Builds the `encoding_groups` array for `--format json --encoding`: the same
+/// `EncodingKind` buckets as `print_by_encoding`, each listing its instructions with
+/// their CPUID features, filtered by `--cpuid`/`--ignore-cpuid` against any one feature
+/// in the instruction's label.
+fn build_encoding_groups_json(
+	encoding_codes: &HashMap<EncodingKind, CpuidInfo>, code_cpuid_label: &HashMap<Code, String>, cmd: &CommandLineOptions, total_instrs: usize,
+) -> Vec<JsonValue> {
+	let cpuid_filter = to_cpuid_filter_vec(cmd.cpuid.clone());
+	let ignore_cpuid_filter = to_cpuid_filter_vec(cmd.ignore_cpuid.clone());
+	let mut kinds: Vec<_> = encoding_codes.keys().copied().collect();
+	kinds.sort_unstable_by_key(|&k| format!("{:?}", k));
+	kinds
+		.into_iter()
+		.filter_map(|kind| {
+			let mut codes: Vec<_> = encoding_codes[&kind]
+				.codes
+				.values()
+				.copied()
+				.filter(|info| {
+					let label = code_cpuid_label.get(&info.code).map(String::as_str).unwrap_or_default();
+					if !cpuid_filter.is_empty() && !cpuid_filter.iter().any(|s| matches_cpuid_in_label(label, s)) {
+						return false;
+					}
+					ignore_cpuid_filter.is_empty() || !ignore_cpuid_filter.iter().any(|s| matches_cpuid_in_label(label, s))
+				})
+				.collect();
+			if codes.is_empty() {
+				return None;
+			}
+			codes.sort_unstable_by_key(|info| (info.code.op_code().instruction_string(), info.code.op_code().op_code_string(), info.code));
+			let group_count: usize = codes.iter().map(|info| info.count).sum();
+			let instrs_json = codes
+				.iter()
+				.map(|info| {
+					let opcode = info.code.op_code();
+					let label = code_cpuid_label.get(&info.code).cloned().unwrap_or_default();
+					JsonValue::Object(vec![
+						("opcode", JsonValue::Str(opcode.op_code_string().to_string())),
+						("instruction", JsonValue::Str(opcode.instruction_string().to_string())),
+						("mnemonic", JsonValue::Str(format!("{:?}", info.code))),
+						("count", JsonValue::UInt(info.count as u64)),
+						("percent", JsonValue::Float((info.count as f64) / (total_instrs as f64) * 100.)),
+						("features", JsonValue::Array(label.split(" and ").map(|f| JsonValue::Str(f.to_string())).collect())),
+					])
+				})
+				.collect();
+			Some(JsonValue::Object(vec![
+				("encoding", JsonValue::Str(format!("{:?}", kind))),
+				("count", JsonValue::UInt(group_count as u64)),
+				("percent", JsonValue::Float((group_count as f64) / (total_instrs as f64) * 100.)),
+				("instructions", JsonValue::Array(instrs_json)),
+			]))
+		})
+		.collect()
+}
+
+/// Builds the `host_check` section: the same `--check-host`/`--target-features`
+/// grouping as `print_missing_on_host`, as data instead of printed lines.
+fn build_host_check_json(all_cpuid: &[(String, Vec<CpuidFeature>, CpuidInfo)], cmd: &CommandLineOptions, total_instrs: usize) -> JsonValue {
+	let available = available_feature_tokens(&cmd.target_features);
+	let cpuid_filter = to_cpuid_filter_vec(cmd.cpuid.clone());
+	let ignore_cpuid_filter = to_cpuid_filter_vec(cmd.ignore_cpuid.clone());
+	let mut missing = Vec::new();
+	let mut unknown_features: Vec<CpuidFeature> = Vec::new();
+	for (cpuid_str, cpuid, info) in all_cpuid {
+		if !cmd.all && cpuid.len() == 1 && should_ignore_cpuid(cpuid[0]) {
+			continue;
+		}
+		if !cpuid_filter.is_empty() && !cpuid_filter.iter().any(|s| matches_cpuid(cpuid_str, s)) {
+			continue;
+		}
+		if !ignore_cpuid_filter.is_empty() && ignore_cpuid_filter.iter().any(|s| matches_cpuid(cpuid_str, s)) {
+			continue;
+		}
+		match host_status(cpuid, &available) {
+			HostStatus::Available => {}
+			HostStatus::Missing => missing.push(cpuid_group_json(cpuid, info, total_instrs)),
+			HostStatus::Unknown => {
+				for &feature in cpuid {
+					if !FEATURE_DETECT_TOKENS.iter().any(|&(f, _)| f == feature) && !unknown_features.contains(&feature) {
+						unknown_features.push(feature);
+					}
+				}
+			}
+		}
+	}
+	unknown_features.sort_unstable_by_key(|&f| format!("{:?}", f));
+	JsonValue::Object(vec![
+		("missing", JsonValue::Array(missing)),
+		("unknown_features", JsonValue::Array(unknown_features.iter().map(|&f| JsonValue::Str(format!("{:?}", f))).collect())),
+	])
+}
+
+/// Builds the `min_level` section: the same data `print_min_level` prints as text.
+fn build_min_level_json(feature_codes: &HashMap<CpuidFeature, CpuidInfo>) -> JsonValue {
+	let max_level = feature_codes.keys().filter_map(|&f| microarch_level(f)).max().unwrap_or(1);
+	let mut forcing: Vec<_> = feature_codes.iter().filter(|&(&f, _)| microarch_level(f) == Some(max_level)).collect();
+	forcing.sort_unstable_by_key(|&(&f, _)| format!("{:?}", f));
+	let forcing_json = if max_level > 1 {
+		forcing
+			.into_iter()
+			.map(|(&feature, info)| {
+				let mut codes: Vec<_> = info.codes.values().copied().collect();
+				codes.sort_unstable_by_key(|info| info.code.op_code().instruction_string());
+				JsonValue::Object(vec![
+					("feature", JsonValue::Str(format!("{:?}", feature))),
+					(
+						"instructions",
+						JsonValue::Array(codes.iter().map(|info| JsonValue::Str(info.code.op_code().instruction_string().to_string())).collect()),
+					),
+				])
+			})
+			.collect()
+	} else {
+		Vec::new()
+	};
+	JsonValue::Object(vec![("level", JsonValue::UInt(max_level as u64)), ("forcing_features", JsonValue::Array(forcing_json))])
+}
+
+/// Builds the `invalid_regions` section: the same data `print_invalid_regions` prints as text.
+fn build_invalid_regions_json(regions: &InvalidRegions) -> JsonValue {
+	JsonValue::Object(vec![
+		("total_bytes", JsonValue::UInt(regions.total_bytes)),
+		("invalid_bytes", JsonValue::UInt(regions.invalid_bytes)),
+		(
+			"regions",
+			JsonValue::Array(
+				regions
+					.spans
+					.iter()
+					.map(|span| {
+						JsonValue::Object(vec![
+							("section", JsonValue::Str(span.section.clone())),
+							("file_offset", JsonValue::UInt(span.file_offset)),
+							("len", JsonValue::UInt(span.len)),
+						])
+					})
+					.collect(),
+			),
+		),
+	])
+}
+
+/// Builds the `attribution` section: the same section -> function -> CPUID tree
+/// `print_attribution` prints as text.
+fn build_attribution_json(attribution: &AttributionInfo, cmd: &CommandLineOptions, total_instrs: usize) -> JsonValue {
+	let cpuid_filter = to_cpuid_filter_vec(cmd.cpuid.clone());
+	let ignore_cpuid_filter = to_cpuid_filter_vec(cmd.ignore_cpuid.clone());
+	let mut section_names: Vec<_> = attribution.sections.keys().cloned().collect();
+	section_names.sort_unstable();
+	let sections_json = section_names
+		.into_iter()
+		.map(|section_name| {
+			let functions = &attribution.sections[&section_name];
+			let mut func_names: Vec<_> = functions.keys().cloned().collect();
+			func_names.sort_unstable();
+			let functions_json = func_names
+				.into_iter()
+				.map(|func_name| {
+					let mut groups: Vec<_> = functions[&func_name]
+						.iter()
+						.filter(|(_, info)| !info.codes.is_empty())
+						.map(|(cpuid, info)| (cpuid.iter().map(|&a| format!("{:?}", a)).collect::<Vec<String>>().join(" and "), cpuid, info))
+						.collect();
+					groups.sort_unstable_by_key(|e| e.0.clone());
+					let cpuid_groups_json: Vec<_> = groups
+						.into_iter()
+						.filter(|(label, cpuid, _)| {
+							if !cmd.all && cpuid.len() == 1 && should_ignore_cpuid(cpuid[0]) {
+								return false;
+							}
+							if !cpuid_filter.is_empty() && !cpuid_filter.iter().any(|s| matches_cpuid(label, s)) {
+								return false;
+							}
+							ignore_cpuid_filter.is_empty() || !ignore_cpuid_filter.iter().any(|s| matches_cpuid(label, s))
+						})
+						.map(|(_, cpuid, info)| cpuid_group_json(cpuid, info, total_instrs))
+						.collect();
+					JsonValue::Object(vec![("function", JsonValue::Str(func_name)), ("cpuid_groups", JsonValue::Array(cpuid_groups_json))])
+				})
+				.collect();
+			JsonValue::Object(vec![("section", JsonValue::Str(section_name)), ("functions", JsonValue::Array(functions_json))])
+		})
+		.collect();
+	JsonValue::Object(vec![("by_symbol", JsonValue::Bool(cmd.by_symbol)), ("sections", JsonValue::Array(sections_json))])
+}
+
+/// Emits the `--format json` document: metadata, then either the CPUID-feature groups
+/// or (under `--encoding`) the encoding groups, always including opcode/instruction/
+/// count/percent so the shape doesn't shift with `--instr`/`--opcode`/`--count`/
+/// `--percent`. Any of the `--check-host`/`--min-level`/`--show-invalid`/`--by-section`/
+/// `--by-symbol` reports that were requested are folded in as extra top-level fields
+/// instead of being printed as separate plain-text blocks after the document.
+#[allow(clippy::too_many_arguments)]
+fn print_json(
+	all_cpuid: &[(String, Vec<CpuidFeature>, CpuidInfo)], encoding_codes: &HashMap<EncodingKind, CpuidInfo>, code_cpuid_label: &HashMap<Code, String>,
+	feature_codes: &HashMap<CpuidFeature, CpuidInfo>, invalid_regions: &InvalidRegions, attribution: &AttributionInfo, cmd: &CommandLineOptions,
+	total_instrs: usize, bitness: u32,
+) -> anyhow::Result<()> {
+	let mut fields = vec![
+		("filename", JsonValue::Str(cmd.filename.to_string_lossy().into_owned())),
+		("bitness", JsonValue::UInt(bitness as u64)),
+		("total_instructions", JsonValue::UInt(total_instrs as u64)),
+	];
+	if cmd.encoding {
+		fields.push(("encoding_groups", JsonValue::Array(build_encoding_groups_json(encoding_codes, code_cpuid_label, cmd, total_instrs))));
+	} else {
+		fields.push(("cpuid_groups", JsonValue::Array(build_cpuid_groups_json(all_cpuid, cmd, total_instrs))));
+	}
+
+	if cmd.check_host || cmd.target_features.is_some() {
+		fields.push(("host_check", build_host_check_json(all_cpuid, cmd, total_instrs)));
+	}
+	if cmd.min_level {
+		fields.push(("min_level", build_min_level_json(feature_codes)));
+	}
+	if cmd.show_invalid {
+		fields.push(("invalid_regions", build_invalid_regions_json(invalid_regions)));
+	}
+	if cmd.by_section || cmd.by_symbol {
+		fields.push(("attribution", build_attribution_json(attribution, cmd, total_instrs)));
+	}
+
+	println!("{}", JsonValue::Object(fields).to_pretty_string());
+	Ok(())
+}
+
+enum HostStatus {
+	Available,
+	Missing,
+	Unknown,
+}
+
+fn host_status(cpuid: &[CpuidFeature], available: &HashSet<String>) -> HostStatus {
+	let mut unknown = false;
+	for &feature in cpuid {
+		match FEATURE_DETECT_TOKENS.iter().find(|&&(f, _)| f == feature) {
+			Some(&(_, token)) => {
+				if !available.contains(token) {
+					return HostStatus::Missing;
+				}
+			}
+			None => unknown = true,
+		}
+	}
+	if unknown {
+		HostStatus::Unknown
+	} else {
+		HostStatus::Available
+	}
+}
+
 fn main() -> anyhow::Result<()> {
 	let cmd = CommandLineOptions::from_args();
 	let show_more_info = cmd.instr || cmd.opcode;
@@ -66,10 +707,18 @@ fn main() -> anyhow::Result<()> {
 
 	let mut all_cpuid1: Vec<(CpuidFeature, CpuidInfo)> = CpuidFeature::values().map(|f| (f, CpuidInfo::default())).collect();
 	let mut all_cpuidn: HashMap<Vec<CpuidFeature>, CpuidInfo> = HashMap::new();
+	let mut feature_codes: HashMap<CpuidFeature, CpuidInfo> = HashMap::new();
+	let mut encoding_codes: HashMap<EncodingKind, CpuidInfo> = HashMap::new();
+	let mut code_cpuid_label: HashMap<Code, String> = HashMap::new();
+	let mut invalid_regions = InvalidRegions::default();
+	let mut attribution = AttributionInfo::default();
+	let function_symbols = if cmd.by_symbol { collect_function_symbols(&file) } else { Vec::new() };
 	let bitness = if file.is_64() { 64 } else { 32 };
 	let mut total_instrs = 0;
 	for section in file.sections().filter(|s| s.kind() == SectionKind::Text) {
 		let decoder_options = if cmd.mpx { DecoderOptions::MPX } else { DecoderOptions::NONE };
+		let section_name = section.name().unwrap_or_default().to_string();
+		let section_file_offset = section.file_range().map_or(0, |(offset, _)| offset);
 		let section_data = section
 			.data()
 			.with_context(|| format!("Couldn't get section data, section `{}` index {}", section.name().unwrap_or_default(), section.index().0))?;
@@ -79,9 +728,49 @@ fn main() -> anyhow::Result<()> {
 		while decoder.can_decode() {
 			total_instrs += 1;
 			decoder.decode_out(&mut instr);
+			if cmd.show_invalid {
+				invalid_regions.total_bytes += instr.len() as u64;
+				if instr.code() == Code::INVALID {
+					invalid_regions.invalid_bytes += instr.len() as u64;
+					let file_offset = section_file_offset + (instr.ip() - section_address);
+					invalid_regions.record_invalid(&section_name, file_offset, instr.len() as u64);
+				}
+			}
 			// Some instructions require multiple CPUID features eg. 'AES and AVX', but if all we're doing
 			// is showing the CPUID feature names, don't show 'xx and yy'
 			let cpuid_features = instr.cpuid_features();
+			if cmd.min_level {
+				for &cpuid in cpuid_features {
+					feature_codes.entry(cpuid).or_default().codes.entry(instr.code()).or_insert(CodeInfo { code: instr.code(), count: 0 }).count += 1;
+				}
+			}
+			if cmd.encoding {
+				encoding_codes
+					.entry(instr.encoding())
+					.or_default()
+					.codes
+					.entry(instr.code())
+					.or_insert(CodeInfo { code: instr.code(), count: 0 })
+					.count += 1;
+				code_cpuid_label
+					.entry(instr.code())
+					.or_insert_with(|| cpuid_features.iter().map(|&a| format!("{:?}", a)).collect::<Vec<String>>().join(" and "));
+			}
+			if cmd.by_section || cmd.by_symbol {
+				let func_name = if cmd.by_symbol { find_symbol(&function_symbols, instr.ip()).unwrap_or("<unknown>").to_string() } else { String::new() };
+				attribution
+					.sections
+					.entry(section_name.clone())
+					.or_default()
+					.entry(func_name)
+					.or_default()
+					.entry(cpuid_features.to_vec())
+					.or_default()
+					.codes
+					.entry(instr.code())
+					.or_insert(CodeInfo { code: instr.code(), count: 0 })
+					.count += 1;
+			}
 			if !show_more_info {
 				for &cpuid in cpuid_features {
 					all_cpuid1[cpuid as usize].1.codes.entry(instr.code()).or_insert(CodeInfo { code: instr.code(), count: 0 }).count += 1;
@@ -98,6 +787,7 @@ fn main() -> anyhow::Result<()> {
 					.count += 1;
 			}
 		}
+		invalid_regions.finish_section();
 	}
 
 	let mut all_cpuid: Vec<_> = all_cpuidn
@@ -112,53 +802,234 @@ fn main() -> anyhow::Result<()> {
 		})
 		.collect();
 	all_cpuid.sort_unstable_by_key(|e| e.0.clone());
-	fn to_cpuid_filter_vec(cpuid: Option<String>) -> Vec<String> {
-		cpuid.unwrap_or_default().split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect::<Vec<_>>()
+	match cmd.format.as_str() {
+		// `--format json` emits a single JSON document, so the `--check-host`/`--min-level`/
+		// `--show-invalid`/`--by-section`/`--by-symbol` reports are folded into it below
+		// instead of being printed as separate plain-text blocks afterwards.
+		"json" => print_json(&all_cpuid, &encoding_codes, &code_cpuid_label, &feature_codes, &invalid_regions, &attribution, &cmd, total_instrs, bitness)?,
+		"text" => {
+			if cmd.encoding {
+				print_by_encoding(&encoding_codes, &code_cpuid_label, &cmd, total_instrs, show_more_info);
+			} else {
+				let cpuid_filter = to_cpuid_filter_vec(cmd.cpuid.clone());
+				let ignore_cpuid_filter = to_cpuid_filter_vec(cmd.ignore_cpuid.clone());
+				for (cpuid_str, cpuid, info) in &all_cpuid {
+					if !cmd.all && cpuid.len() == 1 && should_ignore_cpuid(cpuid[0]) {
+						continue;
+					}
+					if !cpuid_filter.is_empty() && !cpuid_filter.iter().any(|s| matches_cpuid(cpuid_str, s)) {
+						continue;
+					}
+					if !ignore_cpuid_filter.is_empty() && ignore_cpuid_filter.iter().any(|s| matches_cpuid(cpuid_str, s)) {
+						continue;
+					}
+
+					println!("{}", cpuid_str);
+					if show_more_info {
+						print_instruction_breakdown(info, &cmd, total_instrs, "\t");
+					}
+				}
+			}
+
+			if cmd.check_host || cmd.target_features.is_some() {
+				print_missing_on_host(&all_cpuid, &cmd, total_instrs, show_more_info);
+			}
+
+			if cmd.min_level {
+				print_min_level(&feature_codes);
+			}
+
+			if cmd.show_invalid {
+				print_invalid_regions(&invalid_regions);
+			}
+
+			if cmd.by_section || cmd.by_symbol {
+				print_attribution(&attribution, &cmd, total_instrs, show_more_info);
+			}
+		}
+		other => anyhow::bail!("Unknown --format `{}`, expected `text` or `json`", other),
 	}
-	let cpuid_filter = to_cpuid_filter_vec(cmd.cpuid);
-	let ignore_cpuid_filter = to_cpuid_filter_vec(cmd.ignore_cpuid);
+
+	Ok(())
+}
+
+fn to_cpuid_filter_vec(cpuid: Option<String>) -> Vec<String> {
+	cpuid.unwrap_or_default().split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect::<Vec<_>>()
+}
+
+fn matches_cpuid(cpuid: &str, cpuid_pat: &str) -> bool {
+	cpuid == cpuid_pat
+}
+
+/// Like `matches_cpuid`, but for labels that may join several features with `" and "`
+/// (eg. `--encoding`'s per-instruction label): matches if `cpuid_pat` is any one of
+/// those features, not just the whole joined label.
+fn matches_cpuid_in_label(label: &str, cpuid_pat: &str) -> bool {
+	label.split(" and ").any(|feature| feature == cpuid_pat)
+}
+
+fn print_instruction_breakdown(info: &CpuidInfo, cmd: &CommandLineOptions, total_instrs: usize, indent: &str) {
+	let mut codes: Vec<_> = info.codes.values().copied().collect();
+	codes.sort_unstable_by_key(|info| (info.code.op_code().instruction_string(), info.code.op_code().op_code_string(), info.code));
 	let mut output_vec = Vec::new();
-	let mut codes = Vec::new();
+	for info in codes {
+		let opcode = info.code.op_code();
+		output_vec.clear();
+		if cmd.percent {
+			output_vec.push(format!("{:.2}%", (info.count as f64) / (total_instrs as f64) * 100.));
+		}
+		if cmd.count {
+			output_vec.push(format!("{}", info.count));
+		}
+		if cmd.opcode {
+			output_vec.push(opcode.op_code_string().to_string());
+		}
+		if cmd.instr {
+			output_vec.push(opcode.instruction_string().to_string());
+		}
+		println!("{}{}", indent, output_vec.join(" | "));
+	}
+}
+
+struct FunctionSymbol {
+	start: u64,
+	end: u64,
+	name: String,
+}
+
+/// Collects function symbols (non-zero-size `SymbolKind::Text`) sorted by address so
+/// `find_symbol` can binary search them by instruction IP.
+fn collect_function_symbols(file: &File) -> Vec<FunctionSymbol> {
+	let mut symbols: Vec<_> = file
+		.symbols()
+		.filter(|s| s.kind() == SymbolKind::Text && s.size() > 0)
+		.map(|s| FunctionSymbol { start: s.address(), end: s.address() + s.size(), name: s.name().unwrap_or("<unknown>").to_string() })
+		.collect();
+	symbols.sort_unstable_by_key(|s| s.start);
+	symbols
+}
+
+fn find_symbol(symbols: &[FunctionSymbol], address: u64) -> Option<&str> {
+	let idx = symbols.partition_point(|s| s.start <= address);
+	if idx == 0 {
+		return None;
+	}
+	let candidate = &symbols[idx - 1];
+	if address < candidate.end {
+		Some(&candidate.name)
+	} else {
+		None
+	}
+}
+
+type CpuidMap = HashMap<Vec<CpuidFeature>, CpuidInfo>;
+
+/// section name -> function name (empty unless `--by-symbol`) -> CPUID groups -> opcodes
+#[derive(Default)]
+struct AttributionInfo {
+	sections: HashMap<String, HashMap<String, CpuidMap>>,
+}
+
+/// Prints the `--by-section`/`--by-symbol` tree: section -> function -> CPUID features
+/// -> opcodes, reusing the same `--all`/`--cpuid`/`--ignore-cpuid` filters and
+/// `--instr`/`--opcode`/`--count`/`--percent` breakdown as the default output.
+fn print_attribution(attribution: &AttributionInfo, cmd: &CommandLineOptions, total_instrs: usize, show_more_info: bool) {
+	let cpuid_filter = to_cpuid_filter_vec(cmd.cpuid.clone());
+	let ignore_cpuid_filter = to_cpuid_filter_vec(cmd.ignore_cpuid.clone());
+
+	println!("PER-SECTION CPUID USAGE");
+	let mut section_names: Vec<_> = attribution.sections.keys().cloned().collect();
+	section_names.sort_unstable();
+	for section_name in section_names {
+		println!("{}", section_name);
+		let functions = &attribution.sections[&section_name];
+		let mut func_names: Vec<_> = functions.keys().cloned().collect();
+		func_names.sort_unstable();
+		for func_name in func_names {
+			let indent = if cmd.by_symbol {
+				println!("\t{}", func_name);
+				"\t\t"
+			} else {
+				"\t"
+			};
+
+			let mut groups: Vec<_> = functions[&func_name]
+				.iter()
+				.filter(|(_, info)| !info.codes.is_empty())
+				.map(|(cpuid, info)| (cpuid.iter().map(|&a| format!("{:?}", a)).collect::<Vec<String>>().join(" and "), cpuid, info))
+				.collect();
+			groups.sort_unstable_by_key(|e| e.0.clone());
+			for (label, cpuid, info) in groups {
+				if !cmd.all && cpuid.len() == 1 && should_ignore_cpuid(cpuid[0]) {
+					continue;
+				}
+				if !cpuid_filter.is_empty() && !cpuid_filter.iter().any(|s| matches_cpuid(&label, s)) {
+					continue;
+				}
+				if !ignore_cpuid_filter.is_empty() && ignore_cpuid_filter.iter().any(|s| matches_cpuid(&label, s)) {
+					continue;
+				}
+
+				println!("{}{}", indent, label);
+				if show_more_info {
+					print_instruction_breakdown(info, cmd, total_instrs, &format!("{}\t", indent));
+				}
+			}
+		}
+	}
+}
+
+/// Prints the "MISSING ON HOST" report for `--check-host`/`--target-features`: every
+/// CPUID group used by the binary that isn't fully covered by `available`, plus a
+/// separate list of features with no host-detection mapping (assumed present).
+fn print_missing_on_host(all_cpuid: &[(String, Vec<CpuidFeature>, CpuidInfo)], cmd: &CommandLineOptions, total_instrs: usize, show_more_info: bool) {
+	let available = available_feature_tokens(&cmd.target_features);
+	let cpuid_filter = to_cpuid_filter_vec(cmd.cpuid.clone());
+	let ignore_cpuid_filter = to_cpuid_filter_vec(cmd.ignore_cpuid.clone());
+	let mut unknown_features: Vec<CpuidFeature> = Vec::new();
+	let mut any_missing = false;
+
+	println!("MISSING ON HOST");
 	for (cpuid_str, cpuid, info) in all_cpuid {
 		if !cmd.all && cpuid.len() == 1 && should_ignore_cpuid(cpuid[0]) {
 			continue;
 		}
-		fn matches_cpuid(cpuid: &str, cpuid_pat: &str) -> bool {
-			cpuid == cpuid_pat
-		}
-		if !cpuid_filter.is_empty() && !cpuid_filter.iter().any(|s| matches_cpuid(&cpuid_str, s)) {
+		if !cpuid_filter.is_empty() && !cpuid_filter.iter().any(|s| matches_cpuid(cpuid_str, s)) {
 			continue;
 		}
-		if !ignore_cpuid_filter.is_empty() && ignore_cpuid_filter.iter().any(|s| matches_cpuid(&cpuid_str, s)) {
+		if !ignore_cpuid_filter.is_empty() && ignore_cpuid_filter.iter().any(|s| matches_cpuid(cpuid_str, s)) {
 			continue;
 		}
 
-		println!("{}", cpuid_str);
-		if show_more_info {
-			codes.clear();
-			codes.extend(info.codes.values().copied());
-			codes.sort_unstable_by_key(|info| (info.code.op_code().instruction_string(), info.code.op_code().op_code_string(), info.code));
-			for info in codes.drain(..) {
-				let opcode = info.code.op_code();
-				output_vec.clear();
-				if cmd.percent {
-					output_vec.push(format!("{:.2}%", (info.count as f64) / (total_instrs as f64) * 100.));
-				}
-				if cmd.count {
-					output_vec.push(format!("{}", info.count));
+		match host_status(cpuid, &available) {
+			HostStatus::Available => {}
+			HostStatus::Missing => {
+				any_missing = true;
+				println!("{}", cpuid_str);
+				if show_more_info {
+					print_instruction_breakdown(info, cmd, total_instrs, "\t");
 				}
-				if cmd.opcode {
-					output_vec.push(opcode.op_code_string().to_string());
-				}
-				if cmd.instr {
-					output_vec.push(opcode.instruction_string().to_string());
+			}
+			HostStatus::Unknown => {
+				for &feature in cpuid {
+					if !FEATURE_DETECT_TOKENS.iter().any(|&(f, _)| f == feature) && !unknown_features.contains(&feature) {
+						unknown_features.push(feature);
+					}
 				}
-				println!("\t{}", output_vec.join(" | "));
 			}
 		}
 	}
+	if !any_missing {
+		println!("\t(none)");
+	}
 
-	Ok(())
+	if !unknown_features.is_empty() {
+		unknown_features.sort_unstable_by_key(|&f| format!("{:?}", f));
+		println!("UNKNOWN/ASSUMED PRESENT (no host detection mapping)");
+		for feature in unknown_features {
+			println!("\t{:?}", feature);
+		}
+	}
 }
 
 const fn should_ignore_cpuid(cpuid: CpuidFeature) -> bool {